@@ -26,6 +26,48 @@ use net::arrow::proto::ARROW_PROTOCOL_VERSION;
 use net::arrow::proto::codec::{FromBytes, Decode, Encode};
 use net::arrow::proto::error::DecodeError;
 
+use net::arrow::proto::msg::control::ControlMessage;
+
+/// Service ID of the Arrow control channel.
+const CONTROL_SERVICE_ID: u16 = 0x0000;
+
+/// Maximum size (in bytes) of an encoded Arrow Message body accepted by the
+/// decoder.
+///
+/// The payload size is an attacker-controlled `u32` in the header; without an
+/// upper bound a malicious or buggy peer could announce a ~4 GiB payload and
+/// drive unbounded buffering. A header declaring a larger body is rejected
+/// before any allocation takes place.
+pub const MAX_ARROW_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Bit mask selecting the logical 24-bit session ID within the `session` field.
+const SESSION_MASK: u32 = (1 << 24) - 1;
+
+/// Bit mask selecting the payload-encoding bits within the reserved byte.
+const ENCODING_MASK: u8 = 0b0000_0011;
+
+/// Bit mask selecting the control-flag bits within the reserved byte (i.e. the
+/// bits not used by the payload encoding).
+const FLAGS_MASK: u8 = !ENCODING_MASK;
+
+bitflags! {
+    /// In-band control flags carried by the reserved byte of the `session`
+    /// field.
+    ///
+    /// They let the session multiplexer signal fragmentation and stream resets
+    /// without allocating a whole control-service message per event. The low
+    /// two bits of the reserved byte are owned by the payload encoding, so the
+    /// flags occupy the remaining six bits.
+    pub struct ArrowMessageFlags: u8 {
+        /// More fragments of the current logical frame will follow.
+        const MORE_FRAGMENTS = 0b0000_0100;
+        /// The message should be delivered ahead of queued traffic.
+        const URGENT         = 0b0000_1000;
+        /// Reset the associated stream.
+        const RESET          = 0b0001_0000;
+    }
+}
+
 /// Common trait for message body types.
 pub trait MessageBody : Encode {
     /// Get size of the body in bytes.
@@ -39,6 +81,60 @@ impl<T: AsRef<[u8]>> MessageBody for T {
     }
 }
 
+/// A cursor over a byte slice providing bounds-checked, big-endian reads.
+///
+/// Decoding advances the cursor field by field; a read past the end of the
+/// underlying slice yields `None` rather than panicking, which keeps the
+/// codec free of pointer casts and alignment assumptions.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Create a new reader positioned at the start of a given slice.
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    /// Consume and return the next `n` bytes, or `None` if fewer remain.
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.pos + n <= self.buf.len() {
+            let res = &self.buf[self.pos..self.pos + n];
+            self.pos += n;
+            Some(res)
+        } else {
+            None
+        }
+    }
+
+    /// Read a single byte.
+    fn read_u8(&mut self) -> Option<u8> {
+        self.take(1)
+            .map(|b| b[0])
+    }
+
+    /// Read a big-endian `u16`.
+    fn read_u16_be(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|b| ((b[0] as u16) << 8) | (b[1] as u16))
+    }
+
+    /// Read a big-endian `u32`.
+    fn read_u32_be(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| {
+                ((b[0] as u32) << 24)
+                    | ((b[1] as u32) << 16)
+                    | ((b[2] as u32) << 8)
+                    | (b[3] as u32)
+            })
+    }
+}
+
 /// Arrow Message header.
 #[derive(Debug, Copy, Clone)]
 #[repr(packed)]
@@ -47,8 +143,11 @@ pub struct ArrowMessageHeader {
     pub version: u8,
     /// Service ID.
     pub service: u16,
-    /// Session ID (note: the upper 8 bits are reserved).
-    pub session: u32,
+    /// Raw session word: the logical 24-bit session ID in the low bits and the
+    /// reserved byte (encoding/flags) in the upper 8. Access it through
+    /// `session()`, `encoding()` and `flags()` rather than directly, so the
+    /// logical ID and the reserved byte never get confused.
+    session:     u32,
     /// Payload size.
     size:        u32,
 }
@@ -60,10 +159,52 @@ impl ArrowMessageHeader {
         ArrowMessageHeader {
             version: ARROW_PROTOCOL_VERSION,
             service: service,
-            session: session & ((1 << 24) - 1),
+            // clear the reserved byte; encoding/flags are set explicitly
+            session: session & SESSION_MASK,
             size:    size
         }
     }
+
+    /// Get the logical 24-bit session ID.
+    pub fn session(&self) -> u32 {
+        self.session & SESSION_MASK
+    }
+
+    /// Get the raw reserved byte (the upper 8 bits of the `session` field).
+    fn reserved(&self) -> u8 {
+        (self.session >> 24) as u8
+    }
+
+    /// Replace the raw reserved byte, leaving the 24-bit session ID untouched.
+    fn set_reserved(&mut self, reserved: u8) {
+        self.session = (self.session & SESSION_MASK)
+            | ((reserved as u32) << 24);
+    }
+
+    /// Get the payload encoding carried by the reserved byte.
+    pub fn encoding(&self) -> Result<PayloadEncoding, DecodeError> {
+        PayloadEncoding::from_bits(self.reserved())
+    }
+
+    /// Record the payload encoding in the reserved byte, leaving the control
+    /// flags untouched.
+    pub fn set_encoding(&mut self, encoding: PayloadEncoding) {
+        let reserved = (self.reserved() & !ENCODING_MASK) | encoding.to_bits();
+        self.set_reserved(reserved);
+    }
+
+    /// Get the control flags carried by the reserved byte.
+    pub fn flags(&self) -> ArrowMessageFlags {
+        ArrowMessageFlags::from_bits_truncate(self.reserved() & FLAGS_MASK)
+    }
+
+    /// Set the control flags in the reserved byte, leaving the payload-encoding
+    /// bits untouched.
+    pub fn set_flags(&mut self, flags: ArrowMessageFlags) {
+        let reserved = (self.reserved() & !FLAGS_MASK)
+            | (flags.bits() & FLAGS_MASK);
+        self.set_reserved(reserved);
+    }
 }
 
 impl Encode for ArrowMessageHeader {
@@ -81,16 +222,24 @@ impl Encode for ArrowMessageHeader {
 
 impl FromBytes for ArrowMessageHeader {
     fn from_bytes(bytes: &[u8]) -> Result<Option<ArrowMessageHeader>, DecodeError> {
-        assert_eq!(bytes.len(), mem::size_of::<ArrowMessageHeader>());
+        let mut reader = Reader::new(bytes);
 
-        let ptr    = bytes.as_ptr() as *const ArrowMessageHeader;
-        let header = unsafe { &*ptr };
+        let version = reader.read_u8()
+            .ok_or_else(|| DecodeError::from("short Arrow Message header"))?;
+        let service = reader.read_u16_be()
+            .ok_or_else(|| DecodeError::from("short Arrow Message header"))?;
+        let session = reader.read_u32_be()
+            .ok_or_else(|| DecodeError::from("short Arrow Message header"))?;
+        let size    = reader.read_u32_be()
+            .ok_or_else(|| DecodeError::from("short Arrow Message header"))?;
 
         let res = ArrowMessageHeader {
-            version: header.version,
-            service: u16::from_be(header.service),
-            session: u32::from_be(header.session) & ((1 << 24) - 1),
-            size:    u32::from_be(header.size)
+            version: version,
+            // keep the full 32-bit wire value; the logical session ID lives in
+            // the low 24 bits and the reserved byte carries encoding/flags
+            session: session,
+            service: service,
+            size:    size
         };
 
         if res.version == ARROW_PROTOCOL_VERSION {
@@ -108,6 +257,130 @@ pub trait ArrowMessageBody : MessageBody + AsAny + Send {
 impl ArrowMessageBody for Bytes {
 }
 
+/// Typed Arrow Message payload.
+///
+/// The wire `Decode` impl keeps the body as raw `Bytes`; higher layers use
+/// `ArrowMessage::decode_payload` to promote a message into its typed form
+/// once, based on the service ID in the header.
+pub enum ArrowMessagePayload {
+    /// A control-channel message (service ID `0`).
+    Control(ControlMessage),
+    /// An opaque service payload.
+    Service(Bytes),
+}
+
+/// Codec used to (de)compress an Arrow Message body.
+///
+/// Arrow tunnels can carry high-bandwidth camera/service streams; compressing
+/// the body lets a deployment trade CPU for tunnel bandwidth. The chosen
+/// scheme is signalled per message in the low bits of the reserved byte of the
+/// `session` field, so no protocol-version bump is required.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PayloadEncoding {
+    /// No compression; the body is stored verbatim.
+    Identity,
+    /// gzip (RFC 1952).
+    Gzip,
+    /// Zstandard.
+    Zstd,
+}
+
+impl PayloadEncoding {
+    /// Encode the scheme into its 2-bit reserved-byte representation.
+    fn to_bits(self) -> u8 {
+        match self {
+            PayloadEncoding::Identity => 0,
+            PayloadEncoding::Gzip     => 1,
+            PayloadEncoding::Zstd     => 2,
+        }
+    }
+
+    /// Decode the scheme from the reserved byte.
+    fn from_bits(reserved: u8) -> Result<PayloadEncoding, DecodeError> {
+        match reserved & ENCODING_MASK {
+            0 => Ok(PayloadEncoding::Identity),
+            1 => Ok(PayloadEncoding::Gzip),
+            2 => Ok(PayloadEncoding::Zstd),
+            _ => Err(DecodeError::from("unknown payload encoding")),
+        }
+    }
+
+    /// Compress an encoded body according to this scheme.
+    fn compress(self, body: &[u8]) -> Bytes {
+        match self {
+            PayloadEncoding::Identity => Bytes::from(body),
+            PayloadEncoding::Gzip => {
+                use std::io::Write;
+
+                use flate2::Compression;
+                use flate2::write::GzEncoder;
+
+                let mut encoder = GzEncoder::new(
+                    Vec::new(),
+                    Compression::default());
+
+                encoder.write_all(body)
+                    .and_then(|_| encoder.finish())
+                    .map(Bytes::from)
+                    .expect("gzip compression failed")
+            },
+            PayloadEncoding::Zstd => {
+                zstd::encode_all(body, 0)
+                    .map(Bytes::from)
+                    .expect("zstd compression failed")
+            },
+        }
+    }
+
+    /// Decompress a body according to this scheme, inflating at most `limit`
+    /// bytes.
+    ///
+    /// Decompression is otherwise unbounded: a tiny compressed body can inflate
+    /// into gigabytes of output, which would reopen the buffering DoS that the
+    /// wire-size limit guards against. The output is therefore read through a
+    /// capped reader and rejected the moment it exceeds `limit`.
+    fn decompress(self, body: &[u8], limit: usize) -> Result<Bytes, DecodeError> {
+        match self {
+            PayloadEncoding::Identity => Ok(Bytes::from(body)),
+            PayloadEncoding::Gzip => {
+                use flate2::read::GzDecoder;
+
+                read_bounded(GzDecoder::new(body), limit)
+                    .map(Bytes::from)
+            },
+            PayloadEncoding::Zstd => {
+                let decoder = zstd::stream::read::Decoder::new(body)
+                    .map_err(|_| DecodeError::from("invalid zstd payload"))?;
+
+                read_bounded(decoder, limit)
+                    .map(Bytes::from)
+            },
+        }
+    }
+}
+
+/// Read a reader to its end, failing once more than `limit` bytes have been
+/// produced.
+///
+/// Reading one byte past the limit is enough to distinguish an exactly-`limit`
+/// output from an oversized one.
+fn read_bounded<R>(reader: R, limit: usize) -> Result<Vec<u8>, DecodeError>
+    where R: ::std::io::Read {
+    use std::io::Read;
+
+    let mut out = Vec::new();
+
+    reader.take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|_| DecodeError::from("invalid compressed payload"))?;
+
+    if out.len() > limit {
+        Err(DecodeError::from("decompressed payload exceeds maximum size"))
+    } else {
+        Ok(out)
+    }
+}
+
 /// Arrow Message.
 pub struct ArrowMessage {
     /// Message header.
@@ -130,6 +403,79 @@ impl ArrowMessage {
         }
     }
 
+    /// Create a new Arrow Message whose body is compressed with a given
+    /// payload encoding.
+    ///
+    /// The chosen scheme is recorded in the reserved byte of the `session`
+    /// field and the matching decompression step is applied by `decode`.
+    pub fn with_encoding<B>(
+        service: u16,
+        session: u32,
+        body: B,
+        encoding: PayloadEncoding) -> ArrowMessage
+        where B: ArrowMessageBody + 'static {
+        ArrowMessage::build(
+            service,
+            session,
+            body,
+            encoding,
+            ArrowMessageFlags::empty())
+    }
+
+    /// Create a new Arrow Message with a given set of control flags recorded
+    /// in the reserved byte of the `session` field.
+    pub fn new_with_flags<B>(
+        service: u16,
+        session: u32,
+        body: B,
+        flags: ArrowMessageFlags) -> ArrowMessage
+        where B: ArrowMessageBody + 'static {
+        ArrowMessage::build(
+            service,
+            session,
+            body,
+            PayloadEncoding::Identity,
+            flags)
+    }
+
+    /// Create a new Arrow Message combining a payload encoding and a set of
+    /// control flags.
+    ///
+    /// The encoding and flag bits occupy disjoint parts of the reserved byte,
+    /// so they can be set together on a single message.
+    pub fn with_encoding_and_flags<B>(
+        service: u16,
+        session: u32,
+        body: B,
+        encoding: PayloadEncoding,
+        flags: ArrowMessageFlags) -> ArrowMessage
+        where B: ArrowMessageBody + 'static {
+        ArrowMessage::build(service, session, body, encoding, flags)
+    }
+
+    /// Build an Arrow Message with a given encoding and set of control flags.
+    fn build<B>(
+        service: u16,
+        session: u32,
+        body: B,
+        encoding: PayloadEncoding,
+        flags: ArrowMessageFlags) -> ArrowMessage
+        where B: ArrowMessageBody + 'static {
+        let mut payload = BytesMut::with_capacity(body.len());
+
+        body.encode(&mut payload);
+
+        let mut header = ArrowMessageHeader::new(service, session, 0);
+
+        header.set_encoding(encoding);
+        header.set_flags(flags);
+
+        ArrowMessage {
+            header:  header,
+            payload: encoding.compress(payload.as_ref()),
+        }
+    }
+
     /// Get reference to the message header.
     pub fn header(&self) -> ArrowMessageHeader {
         self.header
@@ -139,14 +485,47 @@ impl ArrowMessage {
     pub fn payload(&self) -> &[u8] {
         self.payload.as_ref()
     }
+
+    /// Promote the opaque message body into its typed form.
+    ///
+    /// Messages on the control service (ID `0`) are decoded into a
+    /// `ControlMessage`; all other services keep their raw body. Decoding a
+    /// control frame fails if the body is incomplete or if any trailing bytes
+    /// remain once the message has been parsed.
+    pub fn decode_payload(&self) -> Result<ArrowMessagePayload, DecodeError> {
+        if self.header.service == CONTROL_SERVICE_ID {
+            decode_exact::<ControlMessage>(self.payload.as_ref())
+                .map(ArrowMessagePayload::Control)
+        } else {
+            Ok(ArrowMessagePayload::Service(self.payload.clone()))
+        }
+    }
+}
+
+/// Decode exactly one `T` from a byte slice, failing if the body is incomplete
+/// or if any trailing bytes remain once the message has been parsed.
+fn decode_exact<T: Decode>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let mut buf = BytesMut::from(bytes);
+
+    match T::decode(&mut buf)? {
+        Some(msg) => {
+            if buf.is_empty() {
+                Ok(msg)
+            } else {
+                Err(DecodeError::from("trailing bytes after control message"))
+            }
+        },
+        None => Err(DecodeError::from("incomplete control message")),
+    }
 }
 
 impl Encode for ArrowMessage {
     fn encode(&self, buf: &mut BytesMut) {
-        let header = ArrowMessageHeader::new(
-            self.header.service,
-            self.header.session,
-            self.payload.len() as u32);
+        // copy the header verbatim so the reserved byte (encoding/flags) is
+        // preserved, and only refresh the payload size
+        let mut header = self.header;
+
+        header.size = self.payload.len() as u32;
 
         header.encode(buf);
 
@@ -154,8 +533,15 @@ impl Encode for ArrowMessage {
     }
 }
 
-impl Decode for ArrowMessage {
-    fn decode(buf: &mut BytesMut) -> Result<Option<ArrowMessage>, DecodeError> {
+impl ArrowMessage {
+    /// Decode a single Arrow Message from a given buffer, rejecting any header
+    /// that declares a body larger than `limit`.
+    ///
+    /// The size check happens before any `split_to`/allocation, so an oversized
+    /// header cannot drive buffering.
+    pub fn decode_with_limit(
+        buf: &mut BytesMut,
+        limit: usize) -> Result<Option<ArrowMessage>, DecodeError> {
         let hsize = mem::size_of::<ArrowMessageHeader>();
 
         if buf.len() < hsize {
@@ -163,6 +549,10 @@ impl Decode for ArrowMessage {
         }
 
         if let Some(header) = ArrowMessageHeader::from_bytes(&buf[..hsize])? {
+            if header.size as usize > limit {
+                return Err(DecodeError::from("message exceeds maximum size"));
+            }
+
             let msize = header.size as usize + hsize;
 
             if buf.len() < msize {
@@ -173,6 +563,16 @@ impl Decode for ArrowMessage {
             let payload = message.freeze()
                 .split_off(hsize);
 
+            // decompress the body according to the negotiated encoding and
+            // store it verbatim, clearing the encoding bits so a re-encode of
+            // the decoded message stays consistent
+            let payload = header.encoding()?
+                .decompress(payload.as_ref(), limit)?;
+
+            let mut header = header;
+
+            header.set_encoding(PayloadEncoding::Identity);
+
             let msg = ArrowMessage {
                 header:  header,
                 payload: payload,
@@ -184,3 +584,155 @@ impl Decode for ArrowMessage {
         }
     }
 }
+
+impl Decode for ArrowMessage {
+    fn decode(buf: &mut BytesMut) -> Result<Option<ArrowMessage>, DecodeError> {
+        ArrowMessage::decode_with_limit(buf, MAX_ARROW_MESSAGE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::{Bytes, BytesMut};
+
+    use net::arrow::proto::codec::{Decode, Encode};
+
+    /// Encode a header with a given declared body size followed by `body_len`
+    /// payload bytes.
+    fn framed(size: u32, body_len: usize) -> BytesMut {
+        let mut buf = BytesMut::new();
+
+        ArrowMessageHeader::new(1, 0, size).encode(&mut buf);
+        buf.extend(vec![0u8; body_len]);
+
+        buf
+    }
+
+    #[test]
+    fn oversized_header_is_rejected_before_allocation() {
+        let mut buf = framed(5, 0);
+
+        // the declared size exceeds the limit, so the message must be rejected
+        // even though the body has not been received yet
+        assert!(ArrowMessage::decode_with_limit(&mut buf, 4).is_err());
+    }
+
+    #[test]
+    fn exactly_at_limit_is_accepted() {
+        let mut buf = framed(4, 4);
+
+        let msg = ArrowMessage::decode_with_limit(&mut buf, 4)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(msg.payload().len(), 4);
+    }
+
+    /// Test-only message that consumes a fixed two bytes from the buffer.
+    struct TwoBytes;
+
+    impl Decode for TwoBytes {
+        fn decode(buf: &mut BytesMut) -> Result<Option<TwoBytes>, DecodeError> {
+            if buf.len() < 2 {
+                Ok(None)
+            } else {
+                buf.split_to(2);
+                Ok(Some(TwoBytes))
+            }
+        }
+    }
+
+    #[test]
+    fn decode_payload_passes_service_bodies_through() {
+        let msg = ArrowMessage::new(1, 0, Bytes::from(&b"hello"[..]));
+
+        match msg.decode_payload().unwrap() {
+            ArrowMessagePayload::Service(body) =>
+                assert_eq!(body.as_ref(), b"hello"),
+            _ => panic!("expected a service payload"),
+        }
+    }
+
+    #[test]
+    fn decode_exact_accepts_a_single_complete_message() {
+        assert!(decode_exact::<TwoBytes>(&[0x00, 0x01]).is_ok());
+    }
+
+    #[test]
+    fn decode_exact_rejects_incomplete_input() {
+        assert!(decode_exact::<TwoBytes>(&[0x00]).is_err());
+    }
+
+    #[test]
+    fn decode_exact_rejects_trailing_bytes() {
+        assert!(decode_exact::<TwoBytes>(&[0x00, 0x01, 0x02]).is_err());
+    }
+
+    #[test]
+    fn encoding_round_trips_through_decode() {
+        for &encoding in &[PayloadEncoding::Gzip, PayloadEncoding::Zstd] {
+            let body = Bytes::from(&b"the quick brown fox jumps over the lazy dog"[..]);
+
+            let msg = ArrowMessage::with_encoding_and_flags(
+                3,
+                42,
+                body.clone(),
+                encoding,
+                ArrowMessageFlags::URGENT);
+
+            let mut buf = BytesMut::new();
+
+            msg.encode(&mut buf);
+
+            let decoded = ArrowMessage::decode(&mut buf)
+                .unwrap()
+                .unwrap();
+
+            // the body round-trips and the flags survive alongside the encoding
+            assert_eq!(decoded.payload(), body.as_ref());
+            assert_eq!(decoded.header().flags(), ArrowMessageFlags::URGENT);
+        }
+    }
+
+    #[test]
+    fn corrupt_compressed_payload_is_rejected() {
+        let mut header = ArrowMessageHeader::new(3, 42, 4);
+
+        header.set_encoding(PayloadEncoding::Gzip);
+
+        let mut buf = BytesMut::new();
+
+        header.encode(&mut buf);
+        buf.extend(&[0xFFu8; 4]);
+
+        // an invalid gzip body must surface as a DecodeError, not a panic
+        assert!(ArrowMessage::decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn flags_and_session_round_trip_independently() {
+        let session = 0x00AB_CDEF;
+        let flags   = ArrowMessageFlags::MORE_FRAGMENTS
+            | ArrowMessageFlags::RESET;
+
+        let msg = ArrowMessage::new_with_flags(
+            7,
+            session,
+            Bytes::from(&b"body"[..]),
+            flags);
+
+        let mut buf = BytesMut::new();
+
+        msg.encode(&mut buf);
+
+        let decoded = ArrowMessage::decode(&mut buf)
+            .unwrap()
+            .unwrap();
+
+        // the 24-bit session value and the flag byte stay independent
+        assert_eq!(decoded.header().session(), session);
+        assert_eq!(decoded.header().flags(), flags);
+    }
+}